@@ -1,7 +1,7 @@
 use crate::server::start_server;
-use alloy_primitives::{Address, U256};
+use alloy_primitives::{Address, Bytes, B256, U256};
 use anyhow::Context;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 
 #[derive(Parser, Debug)]
@@ -16,23 +16,72 @@ pub struct Cli {
     /// The port of the Server instance
     #[clap(long, default_value = "3013")]
     port: u16,
+
+    #[clap(flatten)]
+    server_config: ServerConfig,
 }
 
 impl Cli {
     pub async fn run(self) -> anyhow::Result<()> {
-        self.subcmd.execute(self.host, self.port).await
+        self.subcmd
+            .execute(self.host, self.port, self.server_config)
+            .await
     }
 }
 
+/// Options that only apply to `Command::Server`, gathered here so `Cli`
+/// doesn't grow one top-level field per server concern.
+#[derive(Args, Debug, Clone)]
+pub struct ServerConfig {
+    /// Private key loaded as the server's default `SendRaw` signer, so
+    /// signature-gated code paths can be exercised without impersonation
+    #[clap(long)]
+    pub private_key: Option<String>,
+
+    /// Mnemonic phrase used to derive the server's default `SendRaw` signer,
+    /// as an alternative to `--private-key`
+    #[clap(long)]
+    pub mnemonic: Option<String>,
+
+    /// The derivation index to use when `--mnemonic` is set
+    #[clap(long, default_value = "0")]
+    pub mnemonic_index: u32,
+
+    /// Host header values allowed to reach the server; if empty, any host
+    /// is accepted
+    #[clap(long = "allowed-host")]
+    pub allowed_hosts: Vec<String>,
+
+    /// Origins allowed by the server's CORS policy; if empty, any origin
+    /// is accepted
+    #[clap(long = "cors-origin")]
+    pub cors_origins: Vec<String>,
+
+    /// Shared secret required as an `Authorization: Bearer` token on every
+    /// request; if unset, no auth is enforced
+    #[clap(long)]
+    pub auth_token: Option<String>,
+}
+
 #[derive(Subcommand, Debug, Serialize, Deserialize)]
 pub enum Command {
     /// Start an anvil server instance, as well as the http server to accept client commands
     Server {
         fork_url: Option<String>,
 
-        /// The port to start the rpc server on
+        /// The port to start the rpc server on; `0` picks a free port from
+        /// the probed port range
         #[clap(default_value = "8080")]
         rpc_port: u16,
+
+        /// Lower bound of the range probed for a free port when the
+        /// requested command/rpc port is `0` or already taken
+        #[clap(long, default_value = "10000")]
+        port_range_start: u16,
+
+        /// Upper bound (exclusive) of the port range probed for a free port
+        #[clap(long, default_value = "20000")]
+        port_range_end: u16,
     },
     /// Impersonate an account, sending amount from the address to the recipient
     TransferFrom {
@@ -62,19 +111,116 @@ pub enum Command {
         /// The asset to set the balance of in wei
         amount: U256,
     },
+    /// Mint an arbitrary ERC20 balance to an account by writing the token's
+    /// `balanceOf` storage slot directly, without needing to impersonate a
+    /// whale holder
+    Deal {
+        /// The ERC20 token to mint
+        asset: Address,
+        /// The address to credit with `amount`
+        who: Address,
+        /// The balance to set, accounting for the token decimals
+        amount: U256,
+    },
+    /// Take a snapshot of the current chain state, returning an id that can
+    /// later be passed to `Revert` to roll back to this point
+    Snapshot,
+    /// Revert the chain state to a previously taken `Snapshot`
+    Revert {
+        /// The id returned by the `Snapshot` command to revert to
+        id: U256,
+    },
+    /// Run a sequence of commands atomically: a snapshot is taken before the
+    /// batch runs, and automatically reverted if any command in it fails, so
+    /// the whole batch is all-or-nothing
+    Batch {
+        /// The commands to run in order
+        commands: Vec<Command>,
+    },
+    /// Mine a number of blocks, optionally spaced `interval` seconds apart
+    Mine {
+        /// The number of blocks to mine
+        blocks: u64,
+        /// The number of seconds between each mined block
+        interval: Option<u64>,
+    },
+    /// Set the timestamp of the next mined block
+    SetNextBlockTimestamp {
+        /// The unix timestamp the next block should be mined with
+        timestamp: u64,
+    },
+    /// Advance the chain's clock by `seconds` without mining a block
+    IncreaseTime {
+        /// The number of seconds to advance the chain's clock by
+        seconds: u64,
+    },
+    /// Toggle automatic mining of a block for every submitted transaction
+    SetAutoMine {
+        /// Whether automining should be enabled
+        enabled: bool,
+    },
+    /// Mine a new block every `secs` seconds, disabling automine
+    SetIntervalMining {
+        /// The interval, in seconds, between mined blocks
+        secs: u64,
+    },
+    /// Send a real signed transaction through the server's signer pipeline,
+    /// rather than an impersonated one
+    SendRaw {
+        /// The alias of the signer to send from, as configured on the
+        /// server at startup; `None` uses the default signer
+        from_key_alias: Option<String>,
+        /// The transaction recipient
+        to: Address,
+        /// The calldata to send
+        data: Bytes,
+        /// The amount of native currency to send along with the call
+        value: U256,
+    },
+}
+
+/// The result of running a [`Command`] against the server, returned as the
+/// JSON body of the `/command` response.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum CommandResponse {
+    /// The command completed with no result to report
+    Ok,
+    /// The id of a snapshot taken by `Command::Snapshot`
+    SnapshotId(U256),
+    /// The hash of a transaction sent by `Command::SendRaw`
+    TxHash(B256),
 }
 
 impl Command {
-    pub async fn execute(self, host: String, port: u16) -> anyhow::Result<()> {
+    pub async fn execute(
+        self,
+        host: String,
+        port: u16,
+        server_config: ServerConfig,
+    ) -> anyhow::Result<()> {
         match &self {
-            Command::Server { rpc_port, fork_url } => {
+            Command::Server {
+                rpc_port,
+                fork_url,
+                port_range_start,
+                port_range_end,
+            } => {
                 println!("starting command server");
                 println!("command server port: {:?}", port);
                 println!("starting forked anvil server");
                 println!("anvil rpc port: {:?}", rpc_port);
                 println!("with fork url: {:?}", fork_url);
 
-                start_server(host, port, rpc_port.clone(), fork_url.clone()).await?;
+                start_server(
+                    host,
+                    port,
+                    rpc_port.clone(),
+                    fork_url.clone(),
+                    server_config,
+                    (*port_range_start, *port_range_end),
+                )
+                .await?;
             }
             transfer @ Command::TransferFrom {
                 asset,
@@ -100,20 +246,79 @@ impl Command {
                 println!("setting balance of {:?} to {}", who, amount);
                 run_request(host, port, set_balance).await?;
             }
+            deal @ Command::Deal { asset, who, amount } => {
+                println!("dealing {} of {:?} to {:?}", amount, asset, who);
+                run_request(host, port, deal).await?;
+            }
+            snapshot @ Command::Snapshot => {
+                println!("taking snapshot");
+                match run_request(host, port, snapshot).await? {
+                    CommandResponse::SnapshotId(id) => println!("snapshot id: {}", id),
+                    other => println!("unexpected response to Snapshot: {:?}", other),
+                }
+            }
+            revert @ Command::Revert { id } => {
+                println!("reverting to snapshot {}", id);
+                run_request(host, port, revert).await?;
+            }
+            batch @ Command::Batch { commands } => {
+                println!("running batch of {} commands", commands.len());
+                run_request(host, port, batch).await?;
+            }
+            mine @ Command::Mine { blocks, interval } => {
+                println!("mining {} blocks with interval {:?}", blocks, interval);
+                run_request(host, port, mine).await?;
+            }
+            set_timestamp @ Command::SetNextBlockTimestamp { timestamp } => {
+                println!("setting next block timestamp to {}", timestamp);
+                run_request(host, port, set_timestamp).await?;
+            }
+            increase_time @ Command::IncreaseTime { seconds } => {
+                println!("increasing time by {} seconds", seconds);
+                run_request(host, port, increase_time).await?;
+            }
+            set_automine @ Command::SetAutoMine { enabled } => {
+                println!("setting automine to {}", enabled);
+                run_request(host, port, set_automine).await?;
+            }
+            set_interval_mining @ Command::SetIntervalMining { secs } => {
+                println!("setting interval mining to {} seconds", secs);
+                run_request(host, port, set_interval_mining).await?;
+            }
+            send_raw @ Command::SendRaw {
+                from_key_alias,
+                to,
+                value,
+                ..
+            } => {
+                println!(
+                    "sending raw tx from {:?} to {:?} value {}",
+                    from_key_alias, to, value
+                );
+                match run_request(host, port, send_raw).await? {
+                    CommandResponse::TxHash(hash) => println!("tx hash: {}", hash),
+                    other => println!("unexpected response to SendRaw: {:?}", other),
+                }
+            }
         };
 
         Ok(())
     }
 }
 
-async fn run_request<T: Serialize>(host: String, port: u16, command: T) -> anyhow::Result<()> {
+async fn run_request<T: Serialize>(
+    host: String,
+    port: u16,
+    command: T,
+) -> anyhow::Result<CommandResponse> {
     reqwest::Client::new()
         .post(&format!("http://{}:{}/command", host, port))
         .json(&command)
         .send()
         .await
         .context("Failed to connect to server")?
-        .error_for_status()?;
-
-    Ok(())
+        .error_for_status()?
+        .json::<CommandResponse>()
+        .await
+        .context("Failed to parse server response")
 }
@@ -1,5 +1,6 @@
 mod cmd;
 mod server;
+mod wallet;
 use clap::Parser;
 use cmd::Cli;
 
@@ -1,20 +1,32 @@
-use crate::cmd::Command;
-use alloy_primitives::U256;
-use alloy_rpc_types::request::{TransactionInput, TransactionRequest};
+use crate::{
+    cmd::{Command, CommandResponse, ServerConfig},
+    wallet::Wallet,
+};
+use alloy_primitives::{keccak256, Address, Bytes, B256, U256};
+use alloy_rpc_types::{
+    request::{TransactionInput, TransactionRequest},
+    BlockId,
+};
 use alloy_sol_macro::sol;
 use alloy_sol_types::SolCall;
 use anvil::{
     eth::{error::BlockchainError, EthApi},
-    NodeConfig,
+    NodeConfig, NodeHandle,
 };
+use anyhow::Context;
 use axum::{
-    extract::{Json, State},
+    extract::{Json, Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::{self, Next},
     response::IntoResponse,
-    routing::post,
+    routing::{get, post},
     Router,
 };
-use std::sync::Arc;
-use tokio::net::TcpListener;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
+use tokio::{net::TcpListener, sync::Mutex};
+use tower_http::cors::CorsLayer;
 
 sol! {
    /// Interface of the ERC20 standard as defined in [the EIP].
@@ -45,80 +57,587 @@ fn node_config(port: u16, fork_url: Option<String>) -> NodeConfig {
     }
 }
 
+/// Layout a `balanceOf` mapping can take, depending on the compiler that
+/// produced the token: Solidity hashes `keccak256(key . slot)` while Vyper
+/// hashes `keccak256(slot . key)`.
+#[derive(Debug, Clone, Copy)]
+enum MappingLayout {
+    Solidity,
+    Vyper,
+}
+
+/// The storage slot layout discovered for a token's `balanceOf` mapping,
+/// cached so repeated `Deal`s against the same asset skip the probe.
+#[derive(Debug, Clone, Copy)]
+struct BalanceSlot {
+    layout: MappingLayout,
+    index: U256,
+}
+
+/// Per-asset cache of discovered `balanceOf` storage slots, shared across
+/// requests handled by this server instance.
+type SlotCache = Arc<Mutex<HashMap<Address, BalanceSlot>>>;
+
+/// The alias `SendRaw` resolves to when `from_key_alias` is left unset.
+const DEFAULT_SIGNER_ALIAS: &str = "default";
+
+#[derive(Clone)]
+struct AppState {
+    api: Arc<EthApi>,
+    balance_slots: SlotCache,
+    signers: Arc<HashMap<String, Wallet>>,
+    command_port: u16,
+    rpc_port: u16,
+}
+
+/// The body returned by `GET /health`, letting a client discover the ports
+/// this instance actually bound once it confirms the node is answering
+/// requests.
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    chain_id: u64,
+    command_port: u16,
+    rpc_port: u16,
+}
+
+async fn health(State(state): State<AppState>) -> Result<Json<HealthResponse>, ServerError> {
+    let chain_id = state.api.chain_id()?;
+
+    Ok(Json(HealthResponse {
+        chain_id,
+        command_port: state.command_port,
+        rpc_port: state.rpc_port,
+    }))
+}
+
+/// Bind a `TcpListener` for `preferred`, falling back to a bounded probe of
+/// random ports in `range` when `preferred` is `0` or already taken. Each
+/// attempt is a real bind, not a check-then-bind, so there's no window for
+/// another process to steal the port between the check and the use.
+async fn bind_free_port(
+    host: &str,
+    preferred: u16,
+    range: (u16, u16),
+) -> anyhow::Result<TcpListener> {
+    if preferred != 0 {
+        if let Ok(listener) = TcpListener::bind(format!("{host}:{preferred}")).await {
+            return Ok(listener);
+        }
+        println!(
+            "port {preferred} is unavailable, probing {}..{}",
+            range.0, range.1
+        );
+    }
+
+    const MAX_ATTEMPTS: usize = 50;
+    for _ in 0..MAX_ATTEMPTS {
+        let candidate = rand::thread_rng().gen_range(range.0..range.1);
+        if let Ok(listener) = TcpListener::bind(format!("{host}:{candidate}")).await {
+            return Ok(listener);
+        }
+    }
+
+    anyhow::bail!(
+        "failed to bind a free port in {}..{} after {MAX_ATTEMPTS} attempts",
+        range.0,
+        range.1
+    )
+}
+
+/// Host allowlist and optional bearer-token requirement enforced on every
+/// request.
+#[derive(Clone)]
+struct SecurityConfig {
+    allowed_hosts: Arc<Vec<String>>,
+    auth_token: Option<Arc<String>>,
+}
+
+async fn enforce_security(
+    State(config): State<SecurityConfig>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Result<impl IntoResponse, ServerError> {
+    if !config.allowed_hosts.is_empty() {
+        let host = headers
+            .get(header::HOST)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        if !config.allowed_hosts.iter().any(|allowed| allowed == host) {
+            return Err(ServerError::Forbidden(format!(
+                "host {host:?} is not allowlisted"
+            )));
+        }
+    }
+
+    if let Some(token) = &config.auth_token {
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        if bearer != Some(token.as_str()) {
+            return Err(ServerError::Forbidden(
+                "missing or invalid bearer token".to_string(),
+            ));
+        }
+    }
+
+    Ok(next.run(request).await)
+}
+
 pub async fn start_server(
     host: String,
     port: u16,
     rpc_port: u16,
     fork_url: Option<String>,
+    config: ServerConfig,
+    port_range: (u16, u16),
 ) -> anyhow::Result<()> {
-    let (api, _) = anvil::spawn(node_config(rpc_port, fork_url)).await;
-
+    let (api, node_handle) = spawn_anvil(rpc_port, port_range, fork_url).await?;
+    let rpc_port = node_handle.port();
     let api = Arc::new(api);
 
+    let mut signers = HashMap::new();
+    if let Some(key) = &config.private_key {
+        let wallet = Wallet::from_private_key(key, &api).await?;
+        println!("loaded default signer {:?}", wallet.address());
+        signers.insert(DEFAULT_SIGNER_ALIAS.to_string(), wallet);
+    } else if let Some(phrase) = &config.mnemonic {
+        let wallet = Wallet::from_mnemonic(phrase, config.mnemonic_index, &api).await?;
+        println!("loaded default signer {:?}", wallet.address());
+        signers.insert(DEFAULT_SIGNER_ALIAS.to_string(), wallet);
+    }
+
+    let listener = bind_free_port(&host, port, port_range).await?;
+    let command_port = listener.local_addr()?.port();
+
+    let state = AppState {
+        api,
+        balance_slots: Arc::new(Mutex::new(HashMap::new())),
+        signers: Arc::new(signers),
+        command_port,
+        rpc_port,
+    };
+
+    let security = SecurityConfig {
+        allowed_hosts: Arc::new(config.allowed_hosts),
+        auth_token: config.auth_token.map(Arc::new),
+    };
+
+    let cors = if config.cors_origins.is_empty() {
+        CorsLayer::permissive()
+    } else {
+        let origins = config
+            .cors_origins
+            .iter()
+            .map(|origin| origin.parse())
+            .collect::<Result<Vec<_>, _>>()
+            .context("invalid --cors-origin value")?;
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods([Method::POST])
+            .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+    };
+
     let app = Router::new()
         .route("/command", post(handle_command))
-        .with_state(api);
-
-    let listener = TcpListener::bind(format!("{}:{}", host, port)).await?;
+        .route("/eth", post(handle_eth_query))
+        .route("/health", get(health))
+        .with_state(state)
+        .layer(middleware::from_fn_with_state(security, enforce_security))
+        .layer(cors);
 
     println!("command server listening on {}", listener.local_addr()?);
+    println!("anvil rpc listening on port {rpc_port}");
     let _ = axum::serve(listener, app).await?;
 
     Ok(())
 }
 
+/// Spawn anvil's own RPC listener, retrying on a fresh candidate port from
+/// `range` if `preferred` is `0` or it (or a prior candidate) is already
+/// taken.
+async fn spawn_anvil(
+    preferred: u16,
+    range: (u16, u16),
+    fork_url: Option<String>,
+) -> anyhow::Result<(EthApi, NodeHandle)> {
+    if preferred != 0 {
+        if let Some(spawned) = try_spawn_anvil(preferred, fork_url.clone()).await {
+            return Ok(spawned);
+        }
+        println!(
+            "anvil rpc port {preferred} is unavailable, probing {}..{}",
+            range.0, range.1
+        );
+    }
+
+    const MAX_ATTEMPTS: usize = 50;
+    for _ in 0..MAX_ATTEMPTS {
+        let candidate = rand::thread_rng().gen_range(range.0..range.1);
+        if let Some(spawned) = try_spawn_anvil(candidate, fork_url.clone()).await {
+            return Ok(spawned);
+        }
+    }
+
+    anyhow::bail!(
+        "failed to spawn anvil on a free port in {}..{} after {MAX_ATTEMPTS} attempts",
+        range.0,
+        range.1
+    )
+}
+
+/// Run one `anvil::spawn` attempt in its own task, returning `None` if the
+/// bind it performs internally panics, so the caller can try another port.
+async fn try_spawn_anvil(port: u16, fork_url: Option<String>) -> Option<(EthApi, NodeHandle)> {
+    tokio::spawn(async move { anvil::spawn(node_config(port, fork_url)).await })
+        .await
+        .ok()
+}
+
 async fn handle_command(
-    State(api): State<Arc<EthApi>>,
+    State(state): State<AppState>,
     Json(command): Json<Command>,
-) -> Result<(), ServerError> {
-    match command {
-        Command::Server { .. } => {
-            return Err(ServerError::str_err(
-                "Unsupported command `Server`".to_string(),
-            ))
+) -> Result<Json<CommandResponse>, ServerError> {
+    let response = execute_command(&state, command).await?;
+    Ok(Json(response))
+}
+
+/// Run a single [`Command`] against the node, returning its result. Pulled
+/// out of the `handle_command` axum handler so `Command::Batch` can recurse
+/// into it for each of its inner commands.
+fn execute_command<'a>(
+    state: &'a AppState,
+    command: Command,
+) -> Pin<Box<dyn Future<Output = Result<CommandResponse, ServerError>> + Send + 'a>> {
+    let api = state.api.clone();
+    Box::pin(async move {
+        match command {
+            Command::Server { .. } => {
+                return Err(ServerError::str_err(
+                    "Unsupported command `Server`".to_string(),
+                ))
+            }
+            Command::StartImpersonate { who } => {
+                println!("impersonating {:?}", who);
+                api.anvil_impersonate_account(who).await?;
+            }
+            Command::StopImpersonate { who } => {
+                println!("stop impersonating {:?}", who);
+                api.anvil_stop_impersonating_account(who).await?;
+            }
+            Command::TransferFrom {
+                asset,
+                from,
+                to,
+                amount,
+            } => {
+                println!(
+                    "transferring from {:?} to {:?} amount {:?}",
+                    from, to, amount
+                );
+                api.anvil_impersonate_account(from).await?;
+
+                let call = ERC20::transferCall {
+                    to,
+                    amount: U256::from(amount),
+                };
+
+                let tx = TransactionRequest {
+                    to: Some(asset),
+                    input: TransactionInput::new(call.abi_encode().into()),
+                    ..Default::default()
+                };
+
+                let _ = api.send_transaction(tx).await?;
+
+                api.anvil_stop_impersonating_account(from).await?;
+            }
+            Command::SetBalance { amount, who } => {
+                println!("setting balance of {:?} to {:?}", who, amount);
+                api.anvil_set_balance(who, amount).await?;
+            }
+            Command::Deal { asset, who, amount } => {
+                println!("dealing {:?} of {:?} to {:?}", amount, asset, who);
+                deal(&api, &state.balance_slots, asset, who, amount).await?;
+            }
+            Command::Snapshot => {
+                println!("taking snapshot");
+                let id = api.evm_snapshot().await?;
+                return Ok(CommandResponse::SnapshotId(id));
+            }
+            Command::Revert { id } => {
+                println!("reverting to snapshot {:?}", id);
+                if !api.evm_revert(id).await? {
+                    return Err(ServerError::str_err(format!(
+                        "no snapshot found for id {id}"
+                    )));
+                }
+            }
+            Command::Batch { commands } => {
+                println!("running batch of {} commands", commands.len());
+                let snapshot_id = api.evm_snapshot().await?;
+
+                for command in commands {
+                    if let Err(err) = execute_command(state, command).await {
+                        api.evm_revert(snapshot_id).await?;
+                        return Err(err);
+                    }
+                }
+            }
+            Command::Mine { blocks, interval } => {
+                println!("mining {} blocks with interval {:?}", blocks, interval);
+                api.anvil_mine(Some(U256::from(blocks)), interval.map(U256::from))
+                    .await?;
+            }
+            Command::SetNextBlockTimestamp { timestamp } => {
+                println!("setting next block timestamp to {}", timestamp);
+                api.evm_set_next_block_timestamp(timestamp)?;
+            }
+            Command::IncreaseTime { seconds } => {
+                println!("increasing time by {} seconds", seconds);
+                api.evm_increase_time(U256::from(seconds)).await?;
+            }
+            Command::SetAutoMine { enabled } => {
+                println!("setting automine to {}", enabled);
+                api.anvil_set_auto_mine(enabled).await?;
+            }
+            Command::SetIntervalMining { secs } => {
+                println!("setting interval mining to {} seconds", secs);
+                api.anvil_set_interval_mining(secs);
+            }
+            Command::SendRaw {
+                from_key_alias,
+                to,
+                data,
+                value,
+            } => {
+                let alias = from_key_alias
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_SIGNER_ALIAS.to_string());
+                println!("sending raw tx from signer {:?} to {:?}", alias, to);
+
+                let wallet = state.signers.get(&alias).ok_or_else(|| {
+                    ServerError::str_err(format!("no signer registered for alias {alias:?}"))
+                })?;
+
+                let tx = TransactionRequest {
+                    to: Some(to),
+                    input: TransactionInput::new(data),
+                    value: Some(value),
+                    ..Default::default()
+                };
+
+                let hash = wallet.send(&api, tx).await.map_err(ServerError::Anyhow)?;
+                return Ok(CommandResponse::TxHash(hash));
+            }
+        };
+
+        Ok(CommandResponse::Ok)
+    })
+}
+
+/// A read-only query against the `eth` JSON-RPC namespace, served alongside
+/// `/command` so a client can inspect chain state without reaching for the
+/// raw anvil RPC port.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", content = "params")]
+enum EthQuery {
+    #[serde(rename = "eth_getBalance")]
+    GetBalance { address: Address, block: Option<BlockId> },
+    #[serde(rename = "eth_call")]
+    Call {
+        request: TransactionRequest,
+        block: Option<BlockId>,
+    },
+    #[serde(rename = "eth_getStorageAt")]
+    GetStorageAt {
+        address: Address,
+        slot: U256,
+        block: Option<BlockId>,
+    },
+    #[serde(rename = "eth_getCode")]
+    GetCode { address: Address, block: Option<BlockId> },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum EthQueryResponse {
+    Balance(U256),
+    Call(Bytes),
+    StorageAt(B256),
+    Code(Bytes),
+}
+
+async fn handle_eth_query(
+    State(state): State<AppState>,
+    Json(query): Json<EthQuery>,
+) -> Result<Json<EthQueryResponse>, ServerError> {
+    let api = &state.api;
+
+    let response = match query {
+        EthQuery::GetBalance { address, block } => {
+            EthQueryResponse::Balance(api.balance(address, block).await?)
         }
-        Command::StartImpersonate { who } => {
-            println!("impersonating {:?}", who);
-            api.anvil_impersonate_account(who).await?;
+        EthQuery::Call { request, block } => {
+            EthQueryResponse::Call(api.call(request, block, None).await?)
         }
-        Command::StopImpersonate { who } => {
-            println!("stop impersonating {:?}", who);
-            api.anvil_stop_impersonating_account(who).await?;
+        EthQuery::GetStorageAt { address, slot, block } => {
+            EthQueryResponse::StorageAt(api.storage_at(address, slot, block).await?)
         }
-        Command::TransferFrom {
-            asset,
-            from,
-            to,
-            amount,
-        } => {
-            println!(
-                "transferring from {:?} to {:?} amount {:?}",
-                from, to, amount
-            );
-            api.anvil_impersonate_account(from).await?;
-
-            let call = ERC20::transferCall {
-                to,
-                amount: U256::from(amount),
-            };
+        EthQuery::GetCode { address, block } => {
+            EthQueryResponse::Code(api.get_code(address, block).await?)
+        }
+    };
+
+    Ok(Json(response))
+}
+
+/// Mint `amount` of `asset` to `who` by writing the token's `balanceOf`
+/// storage slot directly, auto-detecting the mapping's slot and layout the
+/// first time the asset is dealt and caching it for subsequent calls. Also
+/// bumps `totalSupply` by the same delta when its slot can be found, so
+/// supply-tracking invariants in the protocol keep holding.
+async fn deal(
+    api: &EthApi,
+    slot_cache: &SlotCache,
+    asset: Address,
+    who: Address,
+    amount: U256,
+) -> Result<(), ServerError> {
+    // Held across the whole deal, both sentinel probes included, not just
+    // the lookup, so two concurrent `Deal`s for the same asset can't
+    // interleave their sentinel writes onto the same storage slots.
+    let mut cache = slot_cache.lock().await;
+    let balance_slot = match cache.get(&asset).copied() {
+        Some(slot) => slot,
+        None => {
+            let slot = find_balance_slot(api, asset, who).await?;
+            cache.insert(asset, slot);
+            slot
+        }
+    };
+
+    let slot = balance_mapping_slot(balance_slot.layout, who, balance_slot.index);
+    let previous = U256::from_be_bytes(api.storage_at(asset, slot.into(), None).await?.0);
+    api.anvil_set_storage_at(asset, slot.into(), amount.into())
+        .await?;
+
+    if let Some(total_supply_slot) = find_total_supply_slot(api, asset).await? {
+        let current_supply =
+            U256::from_be_bytes(api.storage_at(asset, total_supply_slot, None).await?.0);
+        let new_supply = if amount >= previous {
+            current_supply.saturating_add(amount - previous)
+        } else {
+            current_supply.saturating_sub(previous - amount)
+        };
+        api.anvil_set_storage_at(asset, total_supply_slot, new_supply.into())
+            .await?;
+    }
+    drop(cache);
+
+    Ok(())
+}
+
+/// Probe candidate `balanceOf` slots `0..=30`, trying both the Solidity and
+/// Vyper mapping layouts: write a sentinel value into the candidate slot and
+/// check whether `balanceOf(who)` reflects it, restoring the original value
+/// either way.
+async fn find_balance_slot(
+    api: &EthApi,
+    asset: Address,
+    who: Address,
+) -> Result<BalanceSlot, ServerError> {
+    const SENTINEL: u64 = 0x1337_1337;
+
+    for index in 0u64..=30 {
+        let index = U256::from(index);
+        for layout in [MappingLayout::Solidity, MappingLayout::Vyper] {
+            let slot = balance_mapping_slot(layout, who, index);
+            let original = api.storage_at(asset, slot.into(), None).await?;
+
+            api.anvil_set_storage_at(asset, slot.into(), B256::from(U256::from(SENTINEL)))
+                .await?;
+            let probed = balance_of(api, asset, who).await;
+            api.anvil_set_storage_at(asset, slot.into(), original)
+                .await?;
+
+            if probed? == U256::from(SENTINEL) {
+                return Ok(BalanceSlot { layout, index });
+            }
+        }
+    }
+
+    Err(ServerError::str_err(format!(
+        "could not detect balanceOf storage slot for {asset:?}"
+    )))
+}
+
+/// Probe candidate `totalSupply` slots `0..=30`, using the same
+/// write-sentinel/read-back/restore technique as `find_balance_slot`: a
+/// slot that merely happens to hold the same value as `totalSupply()` (slot
+/// `0` on a freshly deployed token with zero supply, for instance) is not
+/// good enough evidence, since writing to it would silently clobber an
+/// unrelated storage cell instead of supply.
+async fn find_total_supply_slot(api: &EthApi, asset: Address) -> Result<Option<U256>, ServerError> {
+    const SENTINEL: u64 = 0x1337_1337;
+
+    for index in 0u64..=30 {
+        let slot = U256::from(index);
+        let original = api.storage_at(asset, slot, None).await?;
+
+        api.anvil_set_storage_at(asset, slot, B256::from(U256::from(SENTINEL)))
+            .await?;
+        let probed = total_supply(api, asset).await;
+        api.anvil_set_storage_at(asset, slot, original).await?;
 
-            let tx = TransactionRequest {
-                to: Some(asset),
-                input: TransactionInput::new(call.abi_encode().into()),
-                ..Default::default()
-            };
+        if probed? == U256::from(SENTINEL) {
+            return Ok(Some(slot));
+        }
+    }
 
-            let _ = api.send_transaction(tx).await?;
+    Ok(None)
+}
 
-            api.anvil_stop_impersonating_account(from).await?;
+fn balance_mapping_slot(layout: MappingLayout, who: Address, index: U256) -> B256 {
+    let mut buf = [0u8; 64];
+    match layout {
+        MappingLayout::Solidity => {
+            buf[12..32].copy_from_slice(who.as_slice());
+            buf[32..64].copy_from_slice(&index.to_be_bytes::<32>());
         }
-        Command::SetBalance { amount, who } => {
-            println!("setting balance of {:?} to {:?}", who, amount);
-            api.anvil_set_balance(who, amount).await?;
+        MappingLayout::Vyper => {
+            buf[0..32].copy_from_slice(&index.to_be_bytes::<32>());
+            buf[44..64].copy_from_slice(who.as_slice());
         }
+    }
+    keccak256(buf)
+}
+
+async fn balance_of(api: &EthApi, asset: Address, who: Address) -> Result<U256, ServerError> {
+    let call = ERC20::balanceOfCall { account: who };
+    let tx = TransactionRequest {
+        to: Some(asset),
+        input: TransactionInput::new(call.abi_encode().into()),
+        ..Default::default()
     };
 
-    Ok(())
+    let result = api.call(tx, None, None).await?;
+    Ok(U256::from_be_slice(&result))
+}
+
+async fn total_supply(api: &EthApi, asset: Address) -> Result<U256, ServerError> {
+    let call = ERC20::totalSupplyCall {};
+    let tx = TransactionRequest {
+        to: Some(asset),
+        input: TransactionInput::new(call.abi_encode().into()),
+        ..Default::default()
+    };
+
+    let result = api.call(tx, None, None).await?;
+    Ok(U256::from_be_slice(&result))
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -131,6 +650,9 @@ pub enum ServerError {
 
     #[error("Backend Error {}", .0)]
     BlockchainError(#[from] BlockchainError),
+
+    #[error("Forbidden: {}", .0)]
+    Forbidden(String),
 }
 
 impl ServerError {
@@ -141,8 +663,13 @@ impl ServerError {
 
 impl IntoResponse for ServerError {
     fn into_response(self) -> axum::http::Response<axum::body::Body> {
+        let status = match &self {
+            ServerError::Forbidden(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
         axum::http::Response::builder()
-            .status(500)
+            .status(status)
             .body(axum::body::Body::from(self.to_string()))
             .expect("valid axum response")
     }
@@ -0,0 +1,96 @@
+use alloy_network::TransactionBuilder;
+use alloy_primitives::{Address, B256};
+use alloy_rpc_types::request::TransactionRequest;
+use alloy_signer::Signer;
+use alloy_signer_local::{coins_bip39::English, MnemonicBuilder, PrivateKeySigner};
+use anvil::eth::EthApi;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A signer that can submit real signed transactions, tracking its own
+/// next nonce locally instead of relying on `anvil_impersonate_account`.
+pub struct Wallet {
+    signer: PrivateKeySigner,
+    next_nonce: AtomicU64,
+}
+
+impl Wallet {
+    pub async fn from_private_key(key: &str, api: &EthApi) -> anyhow::Result<Self> {
+        let signer: PrivateKeySigner = key.parse()?;
+        Self::new(signer, api).await
+    }
+
+    pub async fn from_mnemonic(phrase: &str, index: u32, api: &EthApi) -> anyhow::Result<Self> {
+        let signer = MnemonicBuilder::<English>::default()
+            .phrase(phrase)
+            .index(index)?
+            .build()?;
+        Self::new(signer, api).await
+    }
+
+    async fn new(signer: PrivateKeySigner, api: &EthApi) -> anyhow::Result<Self> {
+        let nonce = api.transaction_count(signer.address(), None).await?;
+        Ok(Self {
+            signer,
+            next_nonce: AtomicU64::new(nonce.to::<u64>()),
+        })
+    }
+
+    pub fn address(&self) -> Address {
+        self.signer.address()
+    }
+
+    /// Nonce layer: hand out the next local nonce and never look at the
+    /// node for it again, so back-to-back sends don't collide on a nonce
+    /// the node hasn't seen confirmed yet. Only called once a transaction
+    /// is past every fallible preparation step and is about to be signed
+    /// and broadcast, so a failed gas estimate never burns a nonce.
+    fn take_nonce(&self) -> u64 {
+        self.next_nonce.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Hand `nonce` back if nothing has claimed a later one since, so a
+    /// failed broadcast doesn't permanently wedge the signer behind a gap
+    /// the node will never fill.
+    fn release_nonce(&self, nonce: u64) {
+        let _ = self.next_nonce.compare_exchange(
+            nonce + 1,
+            nonce,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+    }
+
+    /// Gas layer, signer layer, then submission: fill in any gas fields the
+    /// caller left unset, assign the next local nonce, sign, and broadcast
+    /// the raw transaction, returning its hash.
+    pub async fn send(&self, api: &EthApi, mut tx: TransactionRequest) -> anyhow::Result<B256> {
+        tx.set_from(self.signer.address());
+
+        if tx.gas.is_none() {
+            let gas = api.estimate_gas(tx.clone(), None, None).await?;
+            tx.set_gas_limit(gas.to::<u64>());
+        }
+        if tx.gas_price.is_none() {
+            let gas_price = api.gas_price()?;
+            tx.set_gas_price(gas_price.to::<u128>());
+        }
+
+        let nonce = self.take_nonce();
+        tx.set_nonce(nonce);
+
+        match self.sign_and_broadcast(api, tx).await {
+            Ok(hash) => Ok(hash),
+            Err(err) => {
+                self.release_nonce(nonce);
+                Err(err)
+            }
+        }
+    }
+
+    async fn sign_and_broadcast(&self, api: &EthApi, tx: TransactionRequest) -> anyhow::Result<B256> {
+        let envelope = tx.build(&self.signer).await?;
+        let hash = api.send_raw_transaction(envelope.encoded_2718().into()).await?;
+
+        Ok(hash)
+    }
+}